@@ -0,0 +1,192 @@
+use std::{
+    collections::VecDeque,
+    io::Read,
+    sync::{Arc, Barrier, mpsc},
+    thread,
+    time::{Duration, Instant},
+};
+
+use miette::{IntoDiagnostic, Result, miette};
+use reqwest::blocking::{Client, Response};
+use tracing::warn;
+
+/// Cloudflare speed test download endpoint, sized via the `bytes` query parameter.
+const DOWNLOAD_TEST_URL: &str = "https://speed.cloudflare.com/__down";
+
+/// How often cumulative bytes are sampled while a download is in flight.
+const SAMPLE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The trailing window over which the rolling transfer rate is computed.
+const ROLLING_WINDOW: Duration = Duration::from_secs(5);
+
+/// Slack added on top of the time a transfer would need at the throughput floor, so the
+/// client's own request timeout only fires on a genuine hang rather than a healthy transfer
+/// running right at the floor rate.
+const TIMEOUT_SLACK: Duration = Duration::from_secs(15);
+
+/// Bounds how long a single stream may take to establish its TCP/TLS connection, separate
+/// from (and much shorter than) the overall per-stream transfer timeout below. This is what
+/// keeps one straggling connection attempt from holding up the connect-collection phase of
+/// `test_download` for tens of seconds while its siblings sit connected and idle.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds a client whose overall request timeout bounds a transfer that never makes
+/// progress at all (e.g. a connection that stops sending bytes after headers). For
+/// downloads, `read_with_stall_guard`'s rolling-rate check only runs when `read` actually
+/// returns data, so this is what catches a transfer that never starts in the first place;
+/// `test_upload` reuses it for the same reason, since it has no rolling-rate check at all.
+pub(crate) fn build_client(
+    bytes_per_stream: u64,
+    min_throughput_bytes_per_sec: u64,
+) -> Result<Client> {
+    let floor = min_throughput_bytes_per_sec.max(1);
+    let transfer_budget = Duration::from_secs_f64(bytes_per_stream as f64 / floor as f64);
+    Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(transfer_budget + TIMEOUT_SLACK)
+        .build()
+        .into_diagnostic()
+}
+
+pub enum DownloadOutcome {
+    Completed { mbit: f64 },
+    /// The rolling transfer rate stayed below the configured floor for the whole
+    /// rolling window, so the request was abandoned instead of being waited out. Also
+    /// returned when none of the streams managed to connect.
+    Stalled,
+}
+
+/// Downloads `bytes_per_stream` bytes over `streams` concurrent connections and reports
+/// the aggregate rate, aborting as `Stalled` if any stream's rolling transfer rate drops
+/// below `min_throughput_bytes_per_sec` for a full `ROLLING_WINDOW`.
+///
+/// With `streams == 1` this degenerates to a single plain download.
+pub fn test_download(
+    bytes_per_stream: u64,
+    min_throughput_bytes_per_sec: u64,
+    streams: usize,
+) -> Result<DownloadOutcome> {
+    let streams = streams.max(1);
+    let client = build_client(bytes_per_stream, min_throughput_bytes_per_sec)?;
+    let url = format!("{DOWNLOAD_TEST_URL}?bytes={bytes_per_stream}");
+
+    // Connect every stream first, independently and without a barrier, so one stream that
+    // never connects (bounded by `CONNECT_TIMEOUT`, much shorter than the full per-stream
+    // transfer timeout) can't hold up streams that already connected. Only streams that
+    // make it into `connected` enter the read phase.
+    let (connect_tx, connect_rx) = mpsc::channel();
+    for _ in 0..streams {
+        let client = client.clone();
+        let url = url.clone();
+        let connect_tx = connect_tx.clone();
+        thread::spawn(move || {
+            let result = client
+                .get(&url)
+                .send()
+                .into_diagnostic()
+                .and_then(|response| response.error_for_status().into_diagnostic());
+            let _ = connect_tx.send(result);
+        });
+    }
+    drop(connect_tx);
+
+    let mut connected = Vec::new();
+    for result in connect_rx {
+        match result {
+            Ok(response) => connected.push(response),
+            Err(err) => warn!("download stream failed to connect: {err}"),
+        }
+    }
+
+    if connected.is_empty() {
+        return Ok(DownloadOutcome::Stalled);
+    }
+
+    // Now start the clock on all connected streams together, so a slow-to-ramp-up
+    // connection doesn't deflate the aggregate rate.
+    let start_barrier = Arc::new(Barrier::new(connected.len()));
+    let handles: Vec<_> = connected
+        .into_iter()
+        .map(|mut response| {
+            let start_barrier = Arc::clone(&start_barrier);
+            thread::spawn(move || -> Result<(Instant, Instant, u64, bool)> {
+                start_barrier.wait();
+                let start = Instant::now();
+                let (bytes, stalled) =
+                    read_with_stall_guard(&mut response, min_throughput_bytes_per_sec)?;
+                Ok((start, Instant::now(), bytes, stalled))
+            })
+        })
+        .collect();
+
+    let mut total_bytes: u64 = 0;
+    let mut union_start: Option<Instant> = None;
+    let mut union_end: Option<Instant> = None;
+    let mut any_stalled = false;
+
+    for handle in handles {
+        let (start, end, bytes, stalled) = handle
+            .join()
+            .map_err(|_| miette!("download worker thread panicked"))??;
+
+        total_bytes += bytes;
+        any_stalled |= stalled;
+        union_start = Some(union_start.map_or(start, |existing| existing.min(start)));
+        union_end = Some(union_end.map_or(end, |existing| existing.max(end)));
+    }
+
+    if any_stalled {
+        return Ok(DownloadOutcome::Stalled);
+    }
+
+    let elapsed = union_end
+        .unwrap()
+        .duration_since(union_start.unwrap())
+        .as_secs_f64();
+    let mbit = (total_bytes as f64 * 8.0) / elapsed / 1_000_000.0;
+    Ok(DownloadOutcome::Completed { mbit })
+}
+
+/// Reads `response` to completion, tracking the rolling transfer rate and bailing out
+/// early (returning `stalled = true`) once it stays below the floor for `ROLLING_WINDOW`.
+fn read_with_stall_guard(
+    response: &mut Response,
+    min_throughput_bytes_per_sec: u64,
+) -> Result<(u64, bool)> {
+    let mut total_bytes: u64 = 0;
+    let mut samples: VecDeque<(Instant, u64)> = VecDeque::new();
+    let mut last_sample = Instant::now();
+    let mut buf = [0u8; 64 * 1024];
+
+    loop {
+        let n = response.read(&mut buf).into_diagnostic()?;
+        if n == 0 {
+            break;
+        }
+        total_bytes += n as u64;
+
+        let now = Instant::now();
+        if now.duration_since(last_sample) < SAMPLE_INTERVAL {
+            continue;
+        }
+        last_sample = now;
+
+        samples.push_back((now, total_bytes));
+        // Keep the newest sample that is at least `ROLLING_WINDOW` old as the window's start.
+        while samples.len() > 1 && now.duration_since(samples[1].0) >= ROLLING_WINDOW {
+            samples.pop_front();
+        }
+
+        if let Some(&(oldest_time, oldest_bytes)) = samples.front() {
+            let elapsed = now.duration_since(oldest_time).as_secs_f64();
+            if elapsed >= ROLLING_WINDOW.as_secs_f64() {
+                let rate = (total_bytes - oldest_bytes) as f64 / elapsed;
+                if rate < min_throughput_bytes_per_sec as f64 {
+                    return Ok((total_bytes, true));
+                }
+            }
+        }
+    }
+
+    Ok((total_bytes, false))
+}
@@ -1,23 +1,39 @@
 use std::{
+    path::PathBuf,
     str::FromStr,
+    sync::{Arc, Mutex},
     thread,
     time::{Duration, Instant},
 };
 
 use base64::{Engine, engine::general_purpose::STANDARD};
-use cfspeedtest::{
-    OutputFormat,
-    speedtest::{run_latency_test, test_download},
-};
+use cfspeedtest::{OutputFormat, speedtest::run_latency_test};
 use clap::Parser;
 use miette::{IntoDiagnostic, Result, miette};
 use prometheus_remote_write::{LABEL_NAME, Label, Sample, TimeSeries, WriteRequest};
 use reqwest::blocking::Client;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 use tracing_subscriber::{EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 
+mod download;
+mod metrics;
+mod output;
+mod window;
+use download::DownloadOutcome;
+use metrics::{LatestMetrics, SharedMetrics};
+use output::{OutputFormat as OutputFileFormat, OutputRecord};
+use window::{Measurement, Window};
+
 const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
 
+/// Cloudflare speed test upload endpoint, used the same way cfspeedtest drives `/__down`.
+const UPLOAD_TEST_URL: &str = "https://speed.cloudflare.com/__up";
+
+/// Retry budget for the remote-write POST itself, so a brief Prometheus outage doesn't
+/// get counted as a measurement failure.
+const REMOTE_WRITE_MAX_RETRIES: u32 = 5;
+const REMOTE_WRITE_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
 #[derive(Parser, Debug)]
 #[command(
     version,
@@ -33,23 +49,259 @@ struct Args {
     #[arg(short, long, env = "SW_REMOTE_WRITE_URL", default_value_t = String::from("http://localhost:9090/api/v1/write"))]
     remote_write_url: String,
 
-    /// Remote write username
+    /// Remote write username. If unset (along with `password_remote_write`), speedwatch
+    /// skips remote write entirely and runs in pure local-logging mode via `--output-file`.
     #[arg(short, long, env = "SW_REMOTE_WRITE_USERNAME")]
-    username_remote_write: String,
+    username_remote_write: Option<String>,
 
-    /// Remote write password
+    /// Remote write password. If unset (along with `username_remote_write`), speedwatch
+    /// skips remote write entirely and runs in pure local-logging mode via `--output-file`.
     #[arg(short, long, env = "SW_REMOTE_WRITE_PASSWORD")]
-    password_remote_write: String,
+    password_remote_write: Option<String>,
+
+    /// Size in bytes of the buffer to upload for the upload throughput test
+    #[arg(long, default_value_t = 10_000_000)]
+    upload_bytes: u64,
+
+    /// Address to serve a Prometheus `/metrics` pull endpoint on, e.g. `0.0.0.0:9184`.
+    /// When unset, no pull endpoint is served and results are only pushed via remote write.
+    #[arg(long)]
+    metrics_listen: Option<String>,
+
+    /// Number of recent measurements to average over for the `_avg` series
+    #[arg(long, default_value_t = 6)]
+    average_window: usize,
+
+    /// File to persist the averaging window to, so it survives restarts
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// Minimum acceptable download rate, in bytes/sec, before a stalled transfer is aborted
+    #[arg(long, default_value_t = 100_000)]
+    min_throughput_bytes_per_sec: u64,
+
+    /// Number of concurrent connections used to saturate the link for the download test
+    #[arg(long, default_value_t = 1)]
+    parallel_streams: usize,
+
+    /// File to append one record per cycle to, for offline analysis without Prometheus
+    #[arg(long)]
+    output_file: Option<PathBuf>,
+
+    /// Format to use for `--output-file`
+    #[arg(long, value_enum, default_value_t = OutputFileFormat::Csv)]
+    output_format: OutputFileFormat,
+}
+
+/// Uploads a generated buffer of `bytes` length to the test endpoint and returns the
+/// observed throughput in mbit/s, using the same convention as `test_download`.
+fn test_upload(client: &Client, bytes: u64) -> Result<f64> {
+    let payload = vec![0u8; bytes as usize];
+
+    let start = Instant::now();
+    client
+        .post(UPLOAD_TEST_URL)
+        .body(payload)
+        .send()
+        .into_diagnostic()?
+        .error_for_status()
+        .into_diagnostic()?;
+    let elapsed = start.elapsed();
+
+    let mbit = (bytes as f64 * 8.0) / elapsed.as_secs_f64() / 1_000_000.0;
+    Ok(mbit)
+}
+
+/// Runs one measurement cycle and pushes its results, catching any failure so the caller's
+/// interval loop keeps running instead of dying on a single transient error. On failure, a
+/// minimal `sw_internet_test_success`/`sw_internet_test_failures_total` sample is still
+/// pushed (best-effort) so the outage itself is observable in Prometheus, rather than
+/// leaving a silent gap until the next successful cycle.
+fn collect_and_push(
+    args: &Args,
+    shared_metrics: &Option<SharedMetrics>,
+    window: &mut Window,
+    failure_count: &mut u64,
+) -> Result<()> {
+    if let Err(err) = run_cycle(args, shared_metrics, window, failure_count) {
+        *failure_count += 1;
+        warn!("measurement cycle failed, will retry next interval: {err}");
+
+        let hostname = hostname::get()
+            .map(|hostname| hostname.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| "unknown".to_string());
+        let time = current_time_millis().unwrap_or(0);
+        if let Err(push_err) = push_write_request(
+            args,
+            status_timeseries(&hostname, time, 0.0, *failure_count),
+        ) {
+            warn!("failed to push failure status to remote write: {push_err}");
+        }
+    }
+    Ok(())
+}
+
+fn current_time_millis() -> Result<i64> {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .into_diagnostic()?
+        .as_millis()
+        .try_into()
+        .into_diagnostic()
+}
+
+/// The `sw_internet_test_success`/`sw_internet_test_failures_total` pair pushed every cycle,
+/// win or lose, so the tool's own up/down state stays queryable in Prometheus.
+fn status_timeseries(hostname: &str, time: i64, success: f64, failure_count: u64) -> Vec<TimeSeries> {
+    vec![
+        TimeSeries {
+            labels: vec![
+                Label {
+                    name: "hostname".to_string(),
+                    value: hostname.to_string(),
+                },
+                Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "sw_internet_test_success".to_string(),
+                },
+            ],
+            samples: vec![Sample {
+                value: success,
+                timestamp: time,
+            }],
+        },
+        TimeSeries {
+            labels: vec![
+                Label {
+                    name: "hostname".to_string(),
+                    value: hostname.to_string(),
+                },
+                Label {
+                    name: LABEL_NAME.to_string(),
+                    value: "sw_internet_test_failures_total".to_string(),
+                },
+            ],
+            samples: vec![Sample {
+                value: failure_count as f64,
+                timestamp: time,
+            }],
+        },
+    ]
+}
+
+/// Builds and sends the remote-write POST for `timeseries`, retrying with exponential
+/// backoff on transport errors and on a server-error (5xx) response, so a brief Prometheus
+/// outage doesn't need to be treated as a measurement failure. A client-error response
+/// (e.g. bad credentials) isn't retried, since trying again won't change the outcome.
+/// No-ops when remote-write credentials aren't configured.
+fn push_write_request(args: &Args, timeseries: Vec<TimeSeries>) -> Result<()> {
+    let (username, password) = match (
+        args.username_remote_write.as_ref(),
+        args.password_remote_write.as_ref(),
+    ) {
+        (Some(username), Some(password)) => (username, password),
+        (None, None) => return Ok(()),
+        (_, _) => {
+            return Err(miette!(
+                "only one of username_remote_write/password_remote_write is set; both are required for remote write"
+            ));
+        }
+    };
+
+    let write_request = WriteRequest { timeseries };
+
+    let mut req = write_request
+        .build_http_request(
+            &args
+                .remote_write_url
+                .parse::<url::Url>()
+                .into_diagnostic()?,
+            USER_AGENT,
+        )
+        .map_err(|err| miette!("operation failed: {}", err))?;
+
+    let credentials = STANDARD.encode(format!("{username}:{password}"));
+    req.headers_mut().insert(
+        "Authorization",
+        format!("Basic {}", credentials).parse().unwrap(),
+    );
+
+    let client = Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .into_diagnostic()?;
+
+    let (parts, body) = req.into_parts();
+    let method = reqwest::Method::from_str(parts.method.as_str()).into_diagnostic()?;
+
+    let mut attempt = 0;
+    let mut backoff = REMOTE_WRITE_INITIAL_BACKOFF;
+    let response = loop {
+        let mut req_builder = client.request(method.clone(), parts.uri.to_string());
+        for (name, value) in parts.headers.iter() {
+            req_builder = req_builder.header(name.to_string(), value.as_bytes());
+        }
+        req_builder = req_builder.body(body.clone());
+
+        match req_builder.send() {
+            Ok(response) if response.status().is_success() => break response,
+            Ok(response)
+                if response.status().is_server_error() && attempt < REMOTE_WRITE_MAX_RETRIES =>
+            {
+                attempt += 1;
+                let status = response.status();
+                warn!(
+                    "remote write attempt {attempt}/{REMOTE_WRITE_MAX_RETRIES} got server error {status}, retrying in {backoff:?}"
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Ok(response) => {
+                return Err(miette!(
+                    "remote write rejected with status {}",
+                    response.status()
+                ));
+            }
+            Err(err) if attempt < REMOTE_WRITE_MAX_RETRIES => {
+                attempt += 1;
+                warn!(
+                    "remote write attempt {attempt}/{REMOTE_WRITE_MAX_RETRIES} failed, retrying in {backoff:?}: {err}"
+                );
+                thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(err) => return Err(err).into_diagnostic(),
+        }
+    };
+
+    debug!("response status: {}", response.status());
+    Ok(())
 }
 
-fn collect_and_push(args: &Args) -> Result<()> {
+fn run_cycle(
+    args: &Args,
+    shared_metrics: &Option<SharedMetrics>,
+    window: &mut Window,
+    failure_count: &mut u64,
+) -> Result<()> {
     let hostname = hostname::get().into_diagnostic()?;
 
-    let download_speed = test_download(
-        &reqwest::blocking::Client::new(),
+    let download_outcome = download::test_download(
         10_000_000,
-        OutputFormat::None, // don't write to stdout while running the test
-    );
+        args.min_throughput_bytes_per_sec,
+        args.parallel_streams,
+    )?;
+    let download_speed = match download_outcome {
+        DownloadOutcome::Completed { mbit } => Some(mbit),
+        DownloadOutcome::Stalled => {
+            *failure_count += 1;
+            warn!(
+                "download stalled below {} bytes/sec; aborting this cycle's measurement",
+                args.min_throughput_bytes_per_sec
+            );
+            None
+        }
+    };
 
     let (_, avg_latency) = run_latency_test(
         &reqwest::blocking::Client::new(),
@@ -57,30 +309,40 @@ fn collect_and_push(args: &Args) -> Result<()> {
         OutputFormat::None, // don't write to stdout while running the test
     );
 
+    let upload_client =
+        download::build_client(args.upload_bytes, args.min_throughput_bytes_per_sec)?;
+    let upload_speed = test_upload(&upload_client, args.upload_bytes)?;
+
+    if let Some(shared_metrics) = shared_metrics {
+        let mut latest = shared_metrics.lock().expect("metrics lock poisoned");
+        *latest = LatestMetrics {
+            download_mbit: download_speed,
+            upload_mbit: upload_speed,
+            latency_ms: avg_latency,
+        };
+    }
+
     // build write requests
-    let time: i64 = std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .into_diagnostic()?
-        .as_millis()
-        .try_into()
-        .into_diagnostic()?;
+    let time = current_time_millis()?;
 
-    let mut bw_labels: Vec<Label> = vec![];
-    bw_labels.push(Label {
-        name: "hostname".to_string(),
-        value: hostname.to_string_lossy().into_owned(),
-    });
-    bw_labels.push(Label {
-        name: LABEL_NAME.to_string(),
-        value: "sw_internet_bandwidth_mbit".to_string(),
+    let bw_timeseries = download_speed.map(|download_speed| {
+        let mut bw_labels: Vec<Label> = vec![];
+        bw_labels.push(Label {
+            name: "hostname".to_string(),
+            value: hostname.to_string_lossy().into_owned(),
+        });
+        bw_labels.push(Label {
+            name: LABEL_NAME.to_string(),
+            value: "sw_internet_bandwidth_mbit".to_string(),
+        });
+        TimeSeries {
+            labels: bw_labels,
+            samples: vec![Sample {
+                value: download_speed,
+                timestamp: time,
+            }],
+        }
     });
-    let bw_timeseries = TimeSeries {
-        labels: bw_labels,
-        samples: vec![Sample {
-            value: download_speed,
-            timestamp: time,
-        }],
-    };
 
     let mut latency_labels: Vec<Label> = vec![];
     latency_labels.push(Label {
@@ -99,48 +361,91 @@ fn collect_and_push(args: &Args) -> Result<()> {
         }],
     };
 
-    let write_request = WriteRequest {
-        timeseries: vec![bw_timeseries, latency_timeseries],
+    let mut upload_labels: Vec<Label> = vec![];
+    upload_labels.push(Label {
+        name: "hostname".to_string(),
+        value: hostname.to_string_lossy().into_owned(),
+    });
+    upload_labels.push(Label {
+        name: LABEL_NAME.to_string(),
+        value: "sw_internet_upload_mbit".to_string(),
+    });
+    let upload_timeseries = TimeSeries {
+        labels: upload_labels,
+        samples: vec![Sample {
+            value: upload_speed,
+            timestamp: time,
+        }],
     };
 
-    let mut req = write_request
-        .build_http_request(
-            &args
-                .remote_write_url
-                .parse::<url::Url>()
-                .into_diagnostic()?,
-            USER_AGENT,
-        )
-        .map_err(|err| miette!("operation failed: {}", err))?;
-
-    let credentials = STANDARD.encode(format!(
-        "{}:{}",
-        args.username_remote_write, args.password_remote_write
-    ));
-    req.headers_mut().insert(
-        "Authorization",
-        format!("Basic {}", credentials).parse().unwrap(),
-    );
+    window.push(Measurement {
+        timestamp: time,
+        download_mbit: download_speed,
+        upload_mbit: upload_speed,
+        latency_ms: avg_latency,
+    });
+    if let Some(state_file) = &args.state_file {
+        window.save(state_file)?;
+    }
 
-    // send the http::Request
-    let client = Client::builder()
-        .timeout(Duration::from_secs(30))
-        .build()
-        .into_diagnostic()?;
+    if let Some(output_file) = &args.output_file {
+        output::append(
+            output_file,
+            args.output_format,
+            &OutputRecord {
+                timestamp: time,
+                hostname: hostname.to_string_lossy().into_owned(),
+                download_mbit: download_speed,
+                upload_mbit: upload_speed,
+                latency_ms: avg_latency,
+            },
+        )?;
+    }
 
-    let (parts, body) = req.into_parts();
-    let method = reqwest::Method::from_str(parts.method.as_str()).into_diagnostic()?;
-    let mut req_builder = client.request(method, parts.uri.to_string());
-    for (name, value) in parts.headers.iter() {
-        req_builder = req_builder.header(name.to_string(), value.as_bytes());
+    let success = if download_speed.is_some() { 1.0 } else { 0.0 };
+    let mut timeseries = vec![latency_timeseries, upload_timeseries];
+    timeseries.extend(status_timeseries(
+        &hostname.to_string_lossy(),
+        time,
+        success,
+        *failure_count,
+    ));
+    timeseries.extend(bw_timeseries);
+    if let Some(average) = window.average() {
+        let avg_metrics = [
+            ("sw_internet_bandwidth_mbit_avg", average.download_mbit),
+            ("sw_internet_upload_mbit_avg", Some(average.upload_mbit)),
+            ("sw_internet_latency_ms_avg", Some(average.latency_ms)),
+        ];
+        for (metric_name, value) in avg_metrics
+            .into_iter()
+            .filter_map(|(name, value)| value.map(|value| (name, value)))
+        {
+            timeseries.push(TimeSeries {
+                labels: vec![
+                    Label {
+                        name: "hostname".to_string(),
+                        value: hostname.to_string_lossy().into_owned(),
+                    },
+                    Label {
+                        name: LABEL_NAME.to_string(),
+                        value: metric_name.to_string(),
+                    },
+                ],
+                samples: vec![Sample {
+                    value,
+                    timestamp: time,
+                }],
+            });
+        }
     }
-    req_builder = req_builder.body(body);
-    let response = req_builder.send().into_diagnostic()?;
+
+    push_write_request(args, timeseries)?;
 
     info!("time: {}", time);
-    info!("download speed in mbit: {download_speed}");
+    info!("download speed in mbit: {download_speed:?}");
+    info!("upload speed in mbit: {upload_speed}");
     info!("average latency in ms: {avg_latency}");
-    debug!("response status: {}", response.status());
     Ok(())
 }
 
@@ -175,9 +480,30 @@ fn main() -> Result<()> {
 
     info!("Starting speedwatch, with interval: {} minutes", interval);
 
+    let shared_metrics = args.metrics_listen.clone().map(|listen_addr| {
+        let shared_metrics: SharedMetrics = Arc::new(Mutex::new(LatestMetrics::default()));
+        metrics::serve(listen_addr, shared_metrics.clone());
+        shared_metrics
+    });
+
+    let max_age_millis = (args.average_window as i64) * (interval as i64) * 60 * 1000;
+    let now_millis: i64 = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .into_diagnostic()?
+        .as_millis()
+        .try_into()
+        .into_diagnostic()?;
+    let mut window = match &args.state_file {
+        Some(state_file) => {
+            Window::load(state_file, args.average_window, max_age_millis, now_millis)
+        }
+        None => Window::new(args.average_window),
+    };
+    let mut failure_count: u64 = 0;
+
     execute_at_interval(
         || {
-            return collect_and_push(&args);
+            return collect_and_push(&args, &shared_metrics, &mut window, &mut failure_count);
         },
         interval,
     )?;
@@ -0,0 +1,110 @@
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tracing::{debug, warn};
+
+/// Bounds how long a single connection may take to send its request, so an idle or
+/// silent client (health-checker, port scan) can't wedge that connection's handler thread
+/// forever. Generous since scrapers send their request immediately.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most recently measured values, updated by `collect_and_push` and served to scrapers.
+///
+/// `download_mbit` is the only measurement that can come back empty: a stalled download
+/// (see `download::DownloadOutcome::Stalled`) still leaves a good upload/latency reading
+/// for the cycle, and those should keep being served rather than going stale too.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LatestMetrics {
+    pub download_mbit: Option<f64>,
+    pub upload_mbit: f64,
+    pub latency_ms: f64,
+}
+
+pub type SharedMetrics = Arc<Mutex<LatestMetrics>>;
+
+/// Renders the latest measurements in Prometheus text exposition format. The download
+/// gauge is omitted entirely while `download_mbit` is `None`, matching how a stalled
+/// download is left out of the remote-write push rather than reported as zero.
+fn render(metrics: &LatestMetrics) -> String {
+    let mut out = String::new();
+    if let Some(download_mbit) = metrics.download_mbit {
+        out.push_str(&format!(
+            "# HELP sw_internet_bandwidth_mbit Download throughput in mbit/s\n\
+             # TYPE sw_internet_bandwidth_mbit gauge\n\
+             sw_internet_bandwidth_mbit {download_mbit}\n"
+        ));
+    }
+    out.push_str(&format!(
+        "# HELP sw_internet_upload_mbit Upload throughput in mbit/s\n\
+         # TYPE sw_internet_upload_mbit gauge\n\
+         sw_internet_upload_mbit {}\n\
+         # HELP sw_internet_latency_ms Average latency in milliseconds\n\
+         # TYPE sw_internet_latency_ms gauge\n\
+         sw_internet_latency_ms {}\n",
+        metrics.upload_mbit, metrics.latency_ms
+    ));
+    out
+}
+
+/// Serves the latest `SharedMetrics` in Prometheus text exposition format at `/metrics`.
+///
+/// Runs on a dedicated background thread so the caller's measurement loop keeps running
+/// undisturbed; connection handling errors are logged and don't tear down the listener.
+pub fn serve(listen_addr: String, shared: SharedMetrics) {
+    thread::spawn(move || {
+        let listener = match TcpListener::bind(&listen_addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                warn!("failed to bind metrics listener on {listen_addr}: {err}");
+                return;
+            }
+        };
+
+        debug!("serving prometheus metrics on {listen_addr}/metrics");
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    warn!("metrics listener accept failed: {err}");
+                    continue;
+                }
+            };
+
+            // Handle each connection on its own thread, matching the pattern used for
+            // parallel downloads, so one slow or silent client can't block the next scrape.
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || handle_connection(stream, &shared));
+        }
+    });
+}
+
+/// Serves a single `/metrics` request, bounded by `READ_TIMEOUT` so a client that connects
+/// and never sends bytes can't hold its handler thread open indefinitely.
+fn handle_connection(mut stream: TcpStream, shared: &SharedMetrics) {
+    if let Err(err) = stream.set_read_timeout(Some(READ_TIMEOUT)) {
+        warn!("failed to set metrics connection read timeout: {err}");
+        return;
+    }
+
+    let mut buf = [0u8; 1024];
+    if stream.read(&mut buf).is_err() {
+        return;
+    }
+
+    let body = render(&shared.lock().expect("metrics lock poisoned"));
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(),
+        body
+    );
+
+    if let Err(err) = stream.write_all(response.as_bytes()) {
+        warn!("failed writing metrics response: {err}");
+    }
+}
@@ -0,0 +1,143 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::Path,
+};
+
+use clap::ValueEnum;
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutputFormat::Csv => write!(f, "csv"),
+            OutputFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// One cycle's results, as appended to the local output file.
+#[derive(Debug, Serialize)]
+pub struct OutputRecord {
+    pub timestamp: i64,
+    pub hostname: String,
+    pub download_mbit: Option<f64>,
+    pub upload_mbit: f64,
+    pub latency_ms: f64,
+}
+
+/// Appends `record` to `path` in the given `format`, flushing immediately so a killed
+/// process doesn't lose the most recent entry. Writes a CSV header on first creation.
+pub fn append(path: &Path, format: OutputFormat, record: &OutputRecord) -> Result<()> {
+    let is_new = !path.exists();
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .into_diagnostic()?;
+
+    match format {
+        OutputFormat::Csv => {
+            if is_new {
+                writeln!(file, "timestamp,hostname,download_mbit,upload_mbit,latency_ms")
+                    .into_diagnostic()?;
+            }
+            writeln!(
+                file,
+                "{},{},{},{},{}",
+                record.timestamp,
+                record.hostname,
+                record
+                    .download_mbit
+                    .map_or(String::new(), |v| v.to_string()),
+                record.upload_mbit,
+                record.latency_ms
+            )
+            .into_diagnostic()?;
+        }
+        OutputFormat::Json => {
+            let line = serde_json::to_string(record).into_diagnostic()?;
+            writeln!(file, "{line}").into_diagnostic()?;
+        }
+    }
+
+    file.flush().into_diagnostic()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        sync::atomic::{AtomicU32, Ordering},
+    };
+
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("speedwatch-output-test-{name}-{id}"))
+    }
+
+    fn record(download_mbit: Option<f64>) -> OutputRecord {
+        OutputRecord {
+            timestamp: 1_000,
+            hostname: "host".to_string(),
+            download_mbit,
+            upload_mbit: 20.0,
+            latency_ms: 5.0,
+        }
+    }
+
+    #[test]
+    fn csv_writes_header_only_on_first_append() {
+        let path = temp_path("csv-header-once");
+        append(&path, OutputFormat::Csv, &record(Some(100.0))).unwrap();
+        append(&path, OutputFormat::Csv, &record(Some(200.0))).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            contents
+                .matches("timestamp,hostname,download_mbit,upload_mbit,latency_ms")
+                .count(),
+            1
+        );
+        assert_eq!(contents.lines().count(), 3);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn csv_leaves_download_mbit_blank_when_none() {
+        let path = temp_path("csv-blank-download");
+        append(&path, OutputFormat::Csv, &record(None)).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let data_line = contents.lines().nth(1).unwrap();
+        assert_eq!(data_line, "1000,host,,20,5");
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn json_writes_one_record_per_line() {
+        let path = temp_path("json-content");
+        append(&path, OutputFormat::Json, &record(Some(100.0))).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let line = contents.lines().next().unwrap();
+        let parsed: serde_json::Value = serde_json::from_str(line).unwrap();
+        assert_eq!(parsed["timestamp"], 1000);
+        assert_eq!(parsed["download_mbit"], 100.0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,227 @@
+use std::{collections::VecDeque, fs, path::Path};
+
+use miette::{IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+/// A single cycle's measurements, as kept in the sliding window and persisted to the state
+/// file. `download_mbit` is `None` for a cycle whose download stalled (see
+/// `download::DownloadOutcome::Stalled`); the cycle's upload/latency readings are still
+/// good and are kept, the same way `LatestMetrics` handles a stalled download.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct Measurement {
+    pub timestamp: i64,
+    pub download_mbit: Option<f64>,
+    pub upload_mbit: f64,
+    pub latency_ms: f64,
+}
+
+/// The mean of each field across the measurements currently in the window.
+/// `download_mbit` is averaged only over entries that have a reading, and is `None` if
+/// none of them do.
+#[derive(Debug, Clone, Copy)]
+pub struct WindowAverage {
+    pub download_mbit: Option<f64>,
+    pub upload_mbit: f64,
+    pub latency_ms: f64,
+}
+
+/// Ring buffer of the last `capacity` measurements, used to smooth noisy point-in-time readings.
+pub struct Window {
+    entries: VecDeque<Measurement>,
+    capacity: usize,
+}
+
+impl Window {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Loads a window from `path`, dropping any entries older than `max_age_millis`.
+    /// Returns an empty window if the file doesn't exist yet, or if it exists but can't be
+    /// read or parsed (e.g. truncated by a process killed mid-write by `save`, which isn't
+    /// atomic) — a corrupt state file should degrade to a cold start, not crash the tool
+    /// before its interval loop ever gets going.
+    pub fn load(path: &Path, capacity: usize, max_age_millis: i64, now_millis: i64) -> Self {
+        if !path.exists() {
+            return Self::new(capacity);
+        }
+
+        let loaded = fs::read_to_string(path)
+            .into_diagnostic()
+            .and_then(|contents| {
+                serde_json::from_str::<VecDeque<Measurement>>(&contents).into_diagnostic()
+            });
+        let entries = match loaded {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!(
+                    "failed to load window state from {}: {err}; starting with an empty window",
+                    path.display()
+                );
+                return Self::new(capacity);
+            }
+        };
+
+        let mut window = Self::new(capacity);
+        for entry in entries {
+            if now_millis - entry.timestamp <= max_age_millis {
+                window.push(entry);
+            }
+        }
+        window
+    }
+
+    /// Persists the current window contents to `path` as JSON.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_string(&self.entries).into_diagnostic()?;
+        fs::write(path, contents).into_diagnostic()
+    }
+
+    /// A `capacity` of 0 disables the window entirely: nothing is stored and `average`
+    /// always returns `None`.
+    pub fn push(&mut self, measurement: Measurement) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(measurement);
+    }
+
+    /// The mean of each field across the window, or `None` if it's empty.
+    pub fn average(&self) -> Option<WindowAverage> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let count = self.entries.len() as f64;
+        let (mut download_sum, mut download_count, mut upload_sum, mut latency_sum) =
+            (0.0, 0usize, 0.0, 0.0);
+        for entry in &self.entries {
+            if let Some(download_mbit) = entry.download_mbit {
+                download_sum += download_mbit;
+                download_count += 1;
+            }
+            upload_sum += entry.upload_mbit;
+            latency_sum += entry.latency_ms;
+        }
+
+        Some(WindowAverage {
+            download_mbit: (download_count > 0).then(|| download_sum / download_count as f64),
+            upload_mbit: upload_sum / count,
+            latency_ms: latency_sum / count,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    fn measurement(timestamp: i64, download_mbit: Option<f64>) -> Measurement {
+        Measurement {
+            timestamp,
+            download_mbit,
+            upload_mbit: 10.0,
+            latency_ms: 20.0,
+        }
+    }
+
+    /// A fresh, unique path under the OS temp dir, so parallel test runs don't collide.
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        static COUNTER: AtomicU32 = AtomicU32::new(0);
+        let id = COUNTER.fetch_add(1, Ordering::Relaxed);
+        std::env::temp_dir().join(format!("speedwatch-window-test-{name}-{id}.json"))
+    }
+
+    #[test]
+    fn push_evicts_oldest_once_over_capacity() {
+        let mut window = Window::new(2);
+        window.push(measurement(1, Some(1.0)));
+        window.push(measurement(2, Some(2.0)));
+        window.push(measurement(3, Some(3.0)));
+
+        let average = window.average().unwrap();
+        // Entry at timestamp 1 should have been evicted, leaving (2.0 + 3.0) / 2.
+        assert_eq!(average.download_mbit, Some(2.5));
+    }
+
+    #[test]
+    fn push_with_zero_capacity_disables_the_window() {
+        let mut window = Window::new(0);
+        window.push(measurement(1, Some(1.0)));
+        assert!(window.average().is_none());
+    }
+
+    #[test]
+    fn average_of_empty_window_is_none() {
+        let window = Window::new(5);
+        assert!(window.average().is_none());
+    }
+
+    #[test]
+    fn average_ignores_stalled_entries_for_download_but_not_upload_or_latency() {
+        let mut window = Window::new(5);
+        window.push(measurement(1, Some(10.0)));
+        window.push(measurement(2, None));
+
+        let average = window.average().unwrap();
+        assert_eq!(average.download_mbit, Some(10.0));
+        assert_eq!(average.upload_mbit, 10.0);
+        assert_eq!(average.latency_ms, 20.0);
+    }
+
+    #[test]
+    fn average_download_is_none_when_every_entry_stalled() {
+        let mut window = Window::new(5);
+        window.push(measurement(1, None));
+        window.push(measurement(2, None));
+
+        let average = window.average().unwrap();
+        assert_eq!(average.download_mbit, None);
+    }
+
+    #[test]
+    fn load_returns_empty_window_when_file_is_missing() {
+        let path = temp_path("missing");
+        let window = Window::load(&path, 5, 1_000, 1_000);
+        assert!(window.average().is_none());
+    }
+
+    #[test]
+    fn load_falls_back_to_empty_window_on_corrupt_state_file() {
+        let path = temp_path("corrupt");
+        fs::write(&path, b"not valid json").unwrap();
+
+        let window = Window::load(&path, 5, 1_000, 1_000);
+        assert!(window.average().is_none());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_drops_entries_older_than_max_age() {
+        let path = temp_path("age-cutoff");
+        let mut window = Window::new(5);
+        window.push(measurement(0, Some(1.0)));
+        window.push(measurement(900, Some(2.0)));
+        window.save(&path).unwrap();
+
+        let max_age_millis = 500;
+        let now_millis = 1_000;
+        let loaded = Window::load(&path, 5, max_age_millis, now_millis);
+
+        // Only the entry at timestamp 900 is within 500ms of now_millis; the one at 0 is dropped.
+        let average = loaded.average().unwrap();
+        assert_eq!(average.download_mbit, Some(2.0));
+
+        fs::remove_file(&path).unwrap();
+    }
+}